@@ -0,0 +1,241 @@
+//! Content-hashed, lock-protected build cache.
+//!
+//! Each distinct `(manifest, source code)` pair gets its own
+//! `evalrs_cache/<key>/target/` slot, guarded by an advisory file lock so
+//! that concurrent `evalrs` invocations never race on the same `target/`
+//! directory, the way a single shared cache slot (as used previously) would.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use fs2::FileExt;
+
+/// A locked cache slot for one particular `(manifest, source code)` pair.
+///
+/// The advisory lock is held for the lifetime of the value and released on
+/// `Drop`, so it's safe to bail out early (e.g. via `.expect`) without
+/// leaving the slot locked forever.
+pub struct Slot {
+    lock_file: File,
+    target_dir: PathBuf,
+}
+
+impl Slot {
+    /// Computes a stable key by hashing `manifest` and `source_code`
+    /// together, then creates and locks the corresponding cache slot under
+    /// `cache_root`.
+    pub fn acquire(cache_root: &Path, manifest: &str, source_code: &str) -> io::Result<Self> {
+        let slot_dir = cache_root.join(hash_key(manifest, source_code));
+        fs::create_dir_all(&slot_dir)?;
+
+        // Between the `create_dir_all` above and the `File::create` below, a
+        // concurrent `evict_stale` can still observe this slot as unlocked
+        // and stale (we haven't locked it yet) and `remove_dir_all` it out
+        // from under us, so `File::create` would otherwise fail with
+        // `NotFound`. The (now-empty) slot directory is recreated and the
+        // `File::create` retried for as long as evictions keep landing in
+        // that window, since a freshly recreated slot can itself look stale
+        // to an `evict_stale` run with a `max_age` of zero.
+        let lock_file = loop {
+            match File::create(slot_dir.join(".lock")) {
+                Ok(file) => break file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(&slot_dir)?,
+                Err(e) => return Err(e),
+            }
+        };
+        lock_file.lock_exclusive()?;
+
+        Ok(Self {
+            lock_file,
+            target_dir: slot_dir.join("target"),
+        })
+    }
+
+    /// Moves this slot's cached `target/` directory (creating an empty one
+    /// if this is the first build for this key) into `project_target_dir`.
+    pub fn move_in(&self, project_target_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.target_dir)?;
+        fs::rename(&self.target_dir, project_target_dir)
+    }
+
+    /// Moves `project_target_dir` back into this slot so that later
+    /// invocations hashing to the same key reuse the warm `target/`.
+    pub fn move_out(&self, project_target_dir: &Path) -> io::Result<()> {
+        fs::rename(project_target_dir, &self.target_dir)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let _ = self.lock_file.unlock();
+    }
+}
+
+fn hash_key(manifest: &str, source_code: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    manifest.hash(&mut hasher);
+    source_code.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Removes cache slots whose `target/` directory hasn't been modified in
+/// longer than `max_age`, to bound the cache's disk usage over time.
+///
+/// A slot currently held by another `evalrs` invocation is left alone even
+/// if it looks stale: trying (and failing) to lock it first, rather than
+/// deleting based on mtime alone, is what stops eviction from pulling the
+/// directory (and its `.lock` file) out from under whoever holds it.
+pub fn evict_stale(cache_root: &Path, max_age: Duration) -> io::Result<()> {
+    let Ok(entries) = fs::read_dir(cache_root) else {
+        return Ok(());
+    };
+    let now = SystemTime::now();
+    for entry in entries {
+        let path = entry?.path();
+
+        // `target/` is missing while a build is in progress (it's been
+        // `move_in`'d into the project dir) or if that build was
+        // interrupted before `move_out` ran. Falling back to the slot
+        // directory's own mtime means such orphaned slots still age out
+        // instead of being skipped forever.
+        let modified = fs::metadata(path.join("target"))
+            .and_then(|m| m.modified())
+            .or_else(|_| fs::metadata(&path).and_then(|m| m.modified()));
+        let Ok(modified) = modified else {
+            continue;
+        };
+        if now.duration_since(modified).unwrap_or_default() <= max_age {
+            continue;
+        }
+
+        let Ok(lock_file) = File::create(path.join(".lock")) else {
+            continue;
+        };
+        if lock_file.try_lock_exclusive().is_err() {
+            // Someone else is using this slot right now; skip it.
+            continue;
+        }
+        let _ = fs::remove_dir_all(&path);
+        let _ = lock_file.unlock();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn hash_key_is_deterministic() {
+        assert_eq!(
+            hash_key("manifest", "source"),
+            hash_key("manifest", "source")
+        );
+    }
+
+    #[test]
+    fn hash_key_has_no_split_point_collision() {
+        // Without a disambiguating boundary between the two fields, these
+        // would hash identically despite being different (manifest, source)
+        // pairs.
+        assert_ne!(hash_key("ab", "c"), hash_key("a", "bc"));
+    }
+
+    #[test]
+    fn acquire_creates_a_slot_directory_keyed_by_hash() {
+        let cache_root = tempfile::tempdir().unwrap();
+        let slot = Slot::acquire(cache_root.path(), "manifest", "source").unwrap();
+        let expected_dir = cache_root.path().join(hash_key("manifest", "source"));
+        assert!(expected_dir.is_dir());
+        assert_eq!(slot.target_dir, expected_dir.join("target"));
+    }
+
+    #[test]
+    fn evict_stale_skips_a_slot_whose_lock_is_held() {
+        let cache_root = tempfile::tempdir().unwrap();
+        let slot = Slot::acquire(cache_root.path(), "manifest", "source").unwrap();
+        let slot_dir = cache_root.path().join(hash_key("manifest", "source"));
+        fs::create_dir_all(slot_dir.join("target")).unwrap();
+
+        sleep(Duration::from_millis(20));
+        evict_stale(cache_root.path(), Duration::from_millis(1)).unwrap();
+
+        assert!(slot_dir.is_dir(), "held slot must not be evicted");
+        drop(slot);
+    }
+
+    #[test]
+    fn evict_stale_removes_an_unlocked_stale_slot_even_without_target() {
+        let cache_root = tempfile::tempdir().unwrap();
+        // Simulates a build interrupted between `move_in` and `move_out`:
+        // the slot directory exists (with its lock file) but `target/`
+        // doesn't.
+        let slot_dir = cache_root.path().join("orphaned");
+        fs::create_dir_all(&slot_dir).unwrap();
+        File::create(slot_dir.join(".lock")).unwrap();
+
+        sleep(Duration::from_millis(20));
+        evict_stale(cache_root.path(), Duration::from_millis(1)).unwrap();
+
+        assert!(!slot_dir.exists(), "orphaned stale slot must be evicted");
+    }
+
+    #[test]
+    fn acquire_recreates_a_previously_removed_slot_directory() {
+        // `slot_dir` is created and then removed before `acquire` is called,
+        // the state a completed `evict_stale` leaves behind; `acquire` must
+        // recreate it. The window inside `acquire` itself, between its
+        // `create_dir_all` and `File::create(".lock")`, is exercised by
+        // `acquire_retries_past_concurrent_eviction_of_the_same_slot`.
+        let cache_root = tempfile::tempdir().unwrap();
+        let slot_dir = cache_root.path().join(hash_key("manifest", "source"));
+        fs::create_dir_all(&slot_dir).unwrap();
+        fs::remove_dir_all(&slot_dir).unwrap();
+
+        let slot = Slot::acquire(cache_root.path(), "manifest", "source").unwrap();
+        assert!(slot_dir.is_dir());
+        assert_eq!(slot.target_dir, slot_dir.join("target"));
+    }
+
+    #[test]
+    fn acquire_retries_past_concurrent_eviction_of_the_same_slot() {
+        // Stress-exercises the actual race: one thread repeatedly evicts the
+        // (always-stale, by `max_age` zero) slot while another repeatedly
+        // acquires and releases it. Before `acquire` retried on `NotFound`,
+        // this reliably panicked within a handful of iterations.
+        use std::thread;
+
+        let cache_root = tempfile::tempdir().unwrap();
+        let evictor_root = cache_root.path().to_path_buf();
+        let evictor = thread::spawn(move || {
+            for _ in 0..500 {
+                let _ = evict_stale(&evictor_root, Duration::from_nanos(0));
+            }
+        });
+
+        for _ in 0..500 {
+            let slot = Slot::acquire(cache_root.path(), "manifest", "source").unwrap();
+            fs::create_dir_all(&slot.target_dir).unwrap();
+            drop(slot);
+        }
+        evictor.join().unwrap();
+    }
+
+    #[test]
+    fn evict_stale_leaves_a_fresh_unlocked_slot_alone() {
+        let cache_root = tempfile::tempdir().unwrap();
+        let slot = Slot::acquire(cache_root.path(), "manifest", "source").unwrap();
+        let slot_dir = cache_root.path().join(hash_key("manifest", "source"));
+        fs::create_dir_all(slot_dir.join("target")).unwrap();
+        drop(slot); // Releases the lock; the slot is just fresh, not stale.
+
+        evict_stale(cache_root.path(), Duration::from_secs(60)).unwrap();
+
+        assert!(slot_dir.is_dir(), "fresh slot must not be evicted");
+    }
+}
@@ -4,20 +4,73 @@ use std::env;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::process::{self, Command};
+use std::time::Duration;
 use tempfile::Builder;
 
+mod cache;
+
 const TMP_PROJECT_NAME: &str = "evalrs_temp";
 
+/// Rust edition to generate the temporary project's `Cargo.toml` with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edition {
+    E2015,
+    E2018,
+    E2021,
+    E2024,
+}
+
+impl Edition {
+    fn as_str(self) -> &'static str {
+        match self {
+            Edition::E2015 => "2015",
+            Edition::E2018 => "2018",
+            Edition::E2021 => "2021",
+            Edition::E2024 => "2024",
+        }
+    }
+
+    /// `extern crate` is unnecessary from the 2018 edition onward.
+    fn needs_extern_crate(self) -> bool {
+        matches!(self, Edition::E2015)
+    }
+}
+
+impl std::str::FromStr for Edition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2015" => Ok(Edition::E2015),
+            "2018" => Ok(Edition::E2018),
+            "2021" => Ok(Edition::E2021),
+            "2024" => Ok(Edition::E2024),
+            _ => Err(format!(
+                "invalid edition '{s}' (expected one of 2015, 2018, 2021, 2024)"
+            )),
+        }
+    }
+}
+
 struct Args {
-    snippet: Option<String>,
+    snippets: Vec<String>,
     print_result: bool,
     quiet: bool,
     release: bool,
+    edition: Edition,
+    cache_max_age: Option<u64>,
+    forwarded_args: Vec<String>,
+    test: bool,
 }
 
 impl Args {
     fn parse() -> noargs::Result<Option<Self>> {
-        let mut args = noargs::raw_args();
+        // Splits off everything after a `--` separator up front, so that
+        // arguments meant for the evaluated program (e.g.
+        // `evalrs 'fn main() {}' -- foo bar`) are never seen by noargs.
+        let (argv, forwarded_args) = split_forwarded_args(env::args().collect());
+
+        let mut args = noargs::RawArgs::new(argv.into_iter());
         args.metadata_mut().app_name = env!("CARGO_PKG_NAME");
         args.metadata_mut().app_description = "Rust code snippet evaluator";
 
@@ -44,14 +97,45 @@ impl Args {
             .take(&mut args)
             .is_present();
 
-        let snippet = noargs::arg("[SNIPPET]")
+        let test = noargs::flag("test")
+            .doc(concat!(
+                "Compiles and runs the snippet's `#[test]` functions via `cargo test`, ",
+                "instead of wrapping the snippet in `fn main`"
+            ))
+            .take(&mut args)
+            .is_present();
+
+        let edition = noargs::opt("edition")
+            .doc("Rust edition (2015, 2018, 2021 or 2024) to compile the snippet with")
+            .default("2021")
+            .take(&mut args)
+            .then(|a| a.value().parse::<Edition>())?;
+
+        let cache_max_age = noargs::opt("cache-max-age")
             .doc(concat!(
-                "Rust code snippet to be evaluated. ",
-                "If this is omitted, the snippet will be read from the standard input."
+                "Evicts build-cache slots whose 'target/' directory hasn't been touched in ",
+                "more than this many seconds before building. Disabled by default."
             ))
             .take(&mut args)
-            .present()
-            .map(|a| a.value().to_owned());
+            .present_and_then(|a| a.value().parse::<u64>())?;
+
+        let snippet_arg = noargs::arg("[SNIPPET]...").doc(concat!(
+            "Rust code snippet to be evaluated. A single argument is the literal snippet ",
+            "text, same as before. ",
+            "If several are given, they are instead treated as file paths that get read ",
+            "and concatenated into a single project sharing one flat scope (not isolated ",
+            "per-file modules), with `extern crate` declarations and `fn main` merged ",
+            "across all of them; note that this means a single file path (e.g. `a.rs`) ",
+            "is NOT read from disk and is compiled as literal source text instead. ",
+            "If this is omitted, the snippet will be read from the standard input."
+        ));
+        let mut snippets = Vec::new();
+        while let Some(snippet) = snippet_arg
+            .take(&mut args)
+            .present_and_then(|a| a.value().parse::<String>())?
+        {
+            snippets.push(snippet);
+        }
 
         if let Some(help) = args.finish()? {
             print!("{help}");
@@ -59,10 +143,14 @@ impl Args {
         }
 
         Ok(Some(Self {
-            snippet,
+            snippets,
             print_result,
             quiet,
             release,
+            edition,
+            cache_max_age,
+            forwarded_args,
+            test,
         }))
     }
 }
@@ -72,7 +160,20 @@ fn main() -> noargs::Result<()> {
         return Ok(());
     };
 
-    let input = if let Some(snippet) = args.snippet.clone() {
+    if let Err(e) = check_args(&args) {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+
+    let input = if args.snippets.len() > 1 {
+        // Multiple positional arguments are treated as file paths to be
+        // concatenated into one project (sharing one flat scope, not
+        // isolated per-file modules), like cargo-play's `parse_inputs`.
+        read_multi_file_snippet(&args.snippets).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1);
+        })
+    } else if let Some(snippet) = args.snippets.first().cloned() {
         snippet
     } else {
         // Reads standard input stream.
@@ -84,15 +185,23 @@ fn main() -> noargs::Result<()> {
     };
 
     // Makes manifest data and source code.
-    let manifest = make_manifest(&input);
-    let source_code = make_source_code(&input, &args);
+    let manifest = make_manifest(&input, args.edition);
+    let source_code = make_source_code(&input, &args).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    });
 
     // Sets up temporary project.
     let project_dir = Builder::new()
         .prefix(TMP_PROJECT_NAME)
         .tempdir()
         .expect("Cannot create temporary directory");
-    let cache_dir = env::temp_dir().join("evalrs_cache/");
+    let cache_root = env::temp_dir().join("evalrs_cache/");
+    fs::create_dir_all(&cache_root).expect("Cannot create cache directory");
+    if let Some(max_age) = args.cache_max_age {
+        cache::evict_stale(&cache_root, Duration::from_secs(max_age))
+            .expect("Cannot evict stale cache slots");
+    }
     {
         // Writes manifest data to `Cargo.toml` file.
         let manifest_file = project_dir.path().join("Cargo.toml");
@@ -112,19 +221,19 @@ fn main() -> noargs::Result<()> {
             .write_all(source_code.as_bytes())
             .expect("Cannot write to 'main.rs' file");
     }
-    {
-        // Sets up cache data.
-        let target_dir = project_dir.path().join("target/");
-        let cache_target_dir = cache_dir.join("target/");
-        fs::create_dir_all(cache_target_dir.clone())
-            .expect("Cannot create cache 'target/' directory");
-        fs::rename(cache_target_dir, target_dir)
-            .expect("Cannot move 'target/' from cache directory");
-    }
+    // Locks the cache slot keyed on this exact (manifest, source code) pair,
+    // so a concurrent `evalrs` building the same snippet waits instead of
+    // racing us for the same 'target/' directory, and a different snippet
+    // never collides with (or discards) our warm build.
+    let cache_slot = cache::Slot::acquire(&cache_root, &manifest, &source_code)
+        .expect("Cannot acquire build-cache slot");
+    cache_slot
+        .move_in(&project_dir.path().join("target/"))
+        .expect("Cannot move 'target/' from cache directory");
 
-    // Build command
+    // Build (or, under `--test`, build-and-run-tests) command.
     let mut command = Command::new("cargo");
-    command.arg("build");
+    command.arg(if args.test { "test" } else { "build" });
     if args.quiet {
         command.arg("--quiet");
     }
@@ -134,13 +243,15 @@ fn main() -> noargs::Result<()> {
     let mut exit_status = command
         .current_dir(project_dir.path())
         .spawn()
-        .expect("Cannot execute 'cargo build'")
+        .expect("Cannot execute 'cargo' subcommand")
         .wait()
         .expect("Cannot wait cargo process");
 
     // Execute the built command, done separately from building command
-    // to ensure execution in the working directory.
-    if exit_status.success() {
+    // to ensure execution in the working directory. Under `--test`, `cargo
+    // test` above already built and ran the snippet's tests, so there's no
+    // separate binary to spawn.
+    if !args.test && exit_status.success() {
         let path = project_dir
             .path()
             .join("target")
@@ -149,22 +260,22 @@ fn main() -> noargs::Result<()> {
         // At this point the previous exit status was zero, so we're only
         // interested in the new exit status that could potentially be
         // nonzero.
+        //
+        // Stdin is left as the default (inherited from this process), so a
+        // snippet read from a file/positional argument rather than the
+        // standard input stream can still read real input.
         exit_status = Command::new(path)
+            .args(&args.forwarded_args)
             .spawn()
             .expect("Cannot execute the built command")
             .wait()
             .expect("Cannot wait built process");
     }
 
-    // Moves 'target/' to cache directory
-    {
-        let target_dir = project_dir.path().join("target/");
-        let cache_target_dir = cache_dir.join("target/");
-        if !cache_target_dir.exists() {
-            fs::rename(target_dir, cache_target_dir)
-                .expect("Cannot move 'target/' to cache directory");
-        }
-    }
+    // Moves 'target/' back into the cache slot.
+    cache_slot
+        .move_out(&project_dir.path().join("target/"))
+        .expect("Cannot move 'target/' to cache directory");
 
     exit_on_fail(exit_status);
 
@@ -187,60 +298,336 @@ fn exit_on_fail(exs: process::ExitStatus) {
     }
 }
 
-fn make_manifest(input: &str) -> String {
+/// Rejects argument combinations that parse individually but don't make
+/// sense together.
+///
+/// Under `--test`, `cargo test` runs the snippet's tests directly and there's
+/// no built binary left to hand `-- forwarded args` to afterwards (see the
+/// `!args.test` guard around the forwarding in `main`), so a nonempty
+/// forwarded-args list would otherwise be silently swallowed without telling
+/// the user their `-- args` were ignored.
+///
+/// Likewise, `make_source_code` returns from its `args.test` branch before
+/// ever reaching the `args.print_result` handling, so `--print-result` would
+/// otherwise be a silent no-op under `--test` instead of an error.
+fn check_args(args: &Args) -> Result<(), String> {
+    if args.test && !args.forwarded_args.is_empty() {
+        return Err(
+            "--test cannot be combined with forwarded `-- args...`; cargo test runs the \
+             snippet directly and there's no built binary to pass them to"
+                .to_string(),
+        );
+    }
+    if args.test && args.print_result {
+        return Err(
+            "--test cannot be combined with --print-result; cargo test runs the snippet's \
+             #[test] functions directly, so there's no single expression result to print"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Splits `argv` on the first `--` separator, so that everything after it
+/// (meant for the evaluated program, e.g. `evalrs 'fn main() {}' -- foo
+/// bar`) is set aside before noargs ever sees it. Flags like `--test` or
+/// `--edition` appearing after the separator are therefore forwarded
+/// verbatim rather than parsed as evalrs options. Returns `(remaining argv,
+/// forwarded args)`; if there's no `--`, all of `argv` is returned unchanged
+/// with an empty forwarded list.
+fn split_forwarded_args(mut argv: Vec<String>) -> (Vec<String>, Vec<String>) {
+    match argv.iter().position(|a| a == "--") {
+        Some(pos) => {
+            let rest = argv.split_off(pos + 1);
+            argv.pop(); // Drops the "--" marker itself.
+            (argv, rest)
+        }
+        None => (argv, Vec::new()),
+    }
+}
+
+/// Reads and concatenates the snippet files named on the command line.
+///
+/// The files are joined into one flat string, not isolated into per-file
+/// modules, so top-level items (including a lone `fn main`) share a single
+/// scope across all of them; `make_manifest` and `make_source_code` then
+/// scan that whole string, so dependencies declared in any one file and a
+/// `fn main` anywhere among them are picked up regardless of which file they
+/// came from. Two files that each define a same-named top-level item will
+/// fail to compile with a duplicate-definition error, same as if they'd been
+/// pasted into one file by hand.
+fn read_multi_file_snippet(paths: &[String]) -> Result<String, String> {
+    let files = paths
+        .iter()
+        .map(|path| {
+            fs::read_to_string(path).map_err(|e| format!("Cannot read snippet file '{path}': {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(files.join("\n"))
+}
+
+fn make_manifest(input: &str, edition: Edition) -> String {
     let re = Regex::new(r"extern\s+crate\s+([a-z0-9_]+)\s*;(\s*//(.+))?").unwrap();
-    let dependencies = re
-        .captures_iter(input)
-        .map(|cap| {
-            if let Some(value) = cap.get(3) {
-                if value.as_str().contains('=') {
-                    format!("{}\n", value.as_str())
-                } else {
-                    format!("{} = {}\n", &cap[1], value.as_str())
-                }
+    // Keyed by crate name so that two files merged by `read_multi_file_snippet`
+    // declaring the same `extern crate` (a common case once sharing one scope
+    // is the point) don't both land in `[dependencies]`: a duplicate key
+    // there is a hard `cargo` manifest error, not something evalrs could
+    // report more helpfully after the fact. The last occurrence wins, same as
+    // if the lines had been pasted into one file by hand.
+    let mut deps = std::collections::BTreeMap::new();
+    for cap in re.captures_iter(input) {
+        let line = if let Some(value) = cap.get(3) {
+            let value = value.as_str().trim();
+            if value.contains('=') {
+                format!("{}\n", value)
             } else {
-                format!("{} = \"*\"\n", &cap[1])
+                format!("{} = \"{}\"\n", &cap[1], value)
             }
-        })
-        .collect::<String>();
+        } else {
+            format!("{} = \"*\"\n", &cap[1])
+        };
+        deps.insert(cap[1].to_string(), line);
+    }
+    let dependencies = deps.into_values().collect::<String>();
     format!(
         r#"
 [package]
 name = "{}"
 version = "0.0.0"
+edition = "{}"
 
 [dependencies]
 {}
 "#,
-        TMP_PROJECT_NAME, dependencies
+        TMP_PROJECT_NAME,
+        edition.as_str(),
+        dependencies
     )
 }
 
-fn make_source_code(input: &str, args: &Args) -> String {
+fn make_source_code(input: &str, args: &Args) -> Result<String, String> {
     let re = Regex::new(r"(?m)^# ").unwrap();
     let input = re.replace_all(input, "");
 
+    // Checked up front, because under `--test` the snippet is emitted
+    // without a `fn main` wrapper: without this, a `--test` snippet with no
+    // `#[test]` (typo, a custom test macro, or one simply forgotten) would
+    // either fail to compile as a bare expression, or, if it brings its own
+    // `fn main`, have `cargo test` build it, find zero tests, and exit 0
+    // without ever running the snippet's code.
+    if args.test && !Regex::new(r"#\s*\[\s*test\s*\]").unwrap().is_match(&input) {
+        return Err(
+            "--test requires at least one `#[test]` function in the snippet; found none \
+             (cargo test would otherwise report \"running 0 tests\" and exit successfully \
+             without running anything)"
+                .to_string(),
+        );
+    }
+
     if Regex::new(r"(?m)^\s*fn +main *\( *\)")
         .unwrap()
         .is_match(&input)
     {
-        return input.to_string();
+        return Ok(input.to_string());
     }
-    let re = Regex::new(r"(extern\s+crate\s+[a-z0-9_]+\s*;)").unwrap();
-    let crate_lines = re
-        .captures_iter(&input)
-        .map(|cap| format!("{}\n", &cap[1]))
-        .collect::<String>();
+    let re = Regex::new(r"extern\s+crate\s+([a-z0-9_]+)\s*;").unwrap();
+    // `extern crate` lines are still stripped out of the body (the regex
+    // already harvested their crate names into the manifest in `make_manifest`),
+    // but they're only re-emitted into `main.rs` for editions that need them.
+    // Deduped by crate name for the same reason as `make_manifest`: merging
+    // several files can easily carry the same `extern crate` line in more
+    // than one of them, and re-emitting it twice is a duplicate-definition
+    // error (`E0259`), not just redundant.
+    let crate_lines = if args.edition.needs_extern_crate() {
+        let mut seen = std::collections::BTreeSet::new();
+        re.captures_iter(&input)
+            .filter(|cap| seen.insert(cap[1].to_string()))
+            .map(|cap| format!("extern crate {};\n", &cap[1]))
+            .collect::<String>()
+    } else {
+        String::new()
+    };
     let mut body = re.replace_all(&input, "");
+
+    if args.test {
+        // Already confirmed above that the snippet has a `#[test]`
+        // function; under `--test`, such functions are compiled and
+        // executed by `cargo test` itself, so the snippet is emitted as-is
+        // rather than wrapped in `fn main`.
+        return Ok(format!("{}\n{}", crate_lines, body));
+    }
+
     if args.print_result {
         body = Cow::from(format!(r#"println!("{{:?}}", {{ {} }});"#, body));
     }
-    format!(
+    Ok(format!(
         "
 {}
 fn main() {{
 {}
 }}",
         crate_lines, body
-    )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(edition: Edition, test: bool) -> Args {
+        Args {
+            snippets: Vec::new(),
+            print_result: false,
+            quiet: false,
+            release: false,
+            edition,
+            cache_max_age: None,
+            forwarded_args: Vec::new(),
+            test,
+        }
+    }
+
+    #[test]
+    fn make_manifest_embeds_the_requested_edition() {
+        assert!(make_manifest("fn main() {}", Edition::E2015).contains(r#"edition = "2015""#));
+        assert!(make_manifest("fn main() {}", Edition::E2021).contains(r#"edition = "2021""#));
+    }
+
+    #[test]
+    fn make_manifest_collects_extern_crate_dependencies() {
+        let manifest = make_manifest("extern crate regex; // 1.0\nfn main() {}", Edition::E2021);
+        assert!(manifest.contains(r#"regex = "1.0""#));
+    }
+
+    #[test]
+    fn make_manifest_dedups_a_dependency_declared_in_two_merged_files() {
+        // Mirrors read_multi_file_snippet's output: two files, each with
+        // their own `extern crate regex;`, joined into one string.
+        let merged = "extern crate regex; // 1.0\nfn helper() {}\nextern crate regex; // 1.0\nfn main() {}";
+        let manifest = make_manifest(merged, Edition::E2021);
+        assert_eq!(manifest.matches("regex = ").count(), 1);
+    }
+
+    #[test]
+    fn make_source_code_emits_extern_crate_lines_only_pre_2018() {
+        let input = "extern crate regex;\nregex::Regex::new(\".\").unwrap();";
+
+        let e2015 = make_source_code(input, &args(Edition::E2015, false)).unwrap();
+        assert!(e2015.contains("extern crate regex;"));
+
+        let e2021 = make_source_code(input, &args(Edition::E2021, false)).unwrap();
+        assert!(!e2021.contains("extern crate regex;"));
+    }
+
+    #[test]
+    fn make_source_code_dedups_an_extern_crate_declared_in_two_merged_files() {
+        // Mirrors read_multi_file_snippet's output under a pre-2018 edition,
+        // where `extern crate` lines get re-emitted: the same crate declared
+        // in two files must only be re-emitted once, or rustc rejects it as
+        // a duplicate definition (E0259).
+        let merged = "extern crate regex;\nfn helper() {}\nextern crate regex;\nprintln!(\"hi\");";
+        let code = make_source_code(merged, &args(Edition::E2015, false)).unwrap();
+        assert_eq!(code.matches("extern crate regex;").count(), 1);
+    }
+
+    #[test]
+    fn make_source_code_wraps_a_plain_expression_in_fn_main() {
+        let code = make_source_code("1 + 1", &args(Edition::E2021, false)).unwrap();
+        assert!(code.contains("fn main() {"));
+        assert!(code.contains("1 + 1"));
+    }
+
+    #[test]
+    fn make_source_code_under_test_flag_emits_test_fn_as_is_without_wrapping() {
+        let input = "#[test]\nfn it_works() { assert_eq!(1, 1); }";
+        let code = make_source_code(input, &args(Edition::E2021, true)).unwrap();
+        assert!(!code.contains("fn main()"));
+        assert!(code.contains("#[test]"));
+    }
+
+    #[test]
+    fn make_source_code_under_test_flag_errors_without_a_test_attribute() {
+        let err = make_source_code("1 + 1", &args(Edition::E2021, true)).unwrap_err();
+        assert!(err.contains("--test"));
+    }
+
+    #[test]
+    fn check_args_rejects_test_flag_combined_with_forwarded_args() {
+        let mut a = args(Edition::E2021, true);
+        a.forwarded_args = vec!["foo".to_string()];
+        let err = check_args(&a).unwrap_err();
+        assert!(err.contains("--test"));
+    }
+
+    #[test]
+    fn check_args_allows_test_flag_without_forwarded_args() {
+        assert!(check_args(&args(Edition::E2021, true)).is_ok());
+    }
+
+    #[test]
+    fn check_args_rejects_test_flag_combined_with_print_result() {
+        let mut a = args(Edition::E2021, true);
+        a.print_result = true;
+        let err = check_args(&a).unwrap_err();
+        assert!(err.contains("--test"));
+        assert!(err.contains("--print-result"));
+    }
+
+    fn strs(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn split_forwarded_args_separates_evalrs_flags_from_forwarded_args() {
+        let (argv, forwarded) =
+            split_forwarded_args(strs(&["evalrs", "-p", "1 + 1", "--", "foo", "bar"]));
+        assert_eq!(argv, strs(&["evalrs", "-p", "1 + 1"]));
+        assert_eq!(forwarded, strs(&["foo", "bar"]));
+    }
+
+    #[test]
+    fn split_forwarded_args_leaves_flags_after_separator_unparsed_by_noargs() {
+        // Forwarded `--test`/`--edition` belong to the evaluated program,
+        // not evalrs, so they must end up in `forwarded`, not `argv`.
+        let (argv, forwarded) =
+            split_forwarded_args(strs(&["evalrs", "fn main() {}", "--", "--test", "--edition"]));
+        assert_eq!(argv, strs(&["evalrs", "fn main() {}"]));
+        assert_eq!(forwarded, strs(&["--test", "--edition"]));
+    }
+
+    #[test]
+    fn split_forwarded_args_is_a_no_op_without_a_separator() {
+        let (argv, forwarded) = split_forwarded_args(strs(&["evalrs", "1 + 1"]));
+        assert_eq!(argv, strs(&["evalrs", "1 + 1"]));
+        assert!(forwarded.is_empty());
+    }
+
+    #[test]
+    fn read_multi_file_snippet_merges_files_into_one_flat_scope() {
+        let dir = Builder::new().tempdir().unwrap();
+        let a = dir.path().join("a.rs");
+        let b = dir.path().join("b.rs");
+        fs::write(&a, "fn helper() -> i32 { 1 }").unwrap();
+        fs::write(&b, "fn main() { helper(); }").unwrap();
+
+        let merged = read_multi_file_snippet(&[
+            a.to_str().unwrap().to_string(),
+            b.to_str().unwrap().to_string(),
+        ])
+        .unwrap();
+
+        assert!(merged.contains("fn helper() -> i32 { 1 }"));
+        assert!(merged.contains("fn main() { helper(); }"));
+        // Both files land in one string, not isolated per-file modules, so a
+        // lone `fn main` from the second file is visible to `make_source_code`.
+        assert!(Regex::new(r"(?m)^\s*fn +main *\( *\)")
+            .unwrap()
+            .is_match(&merged));
+    }
+
+    #[test]
+    fn read_multi_file_snippet_errors_cleanly_on_a_missing_file() {
+        let err = read_multi_file_snippet(&["nonexistent.rs".to_string()]).unwrap_err();
+        assert!(err.contains("nonexistent.rs"));
+    }
 }